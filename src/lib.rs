@@ -41,11 +41,14 @@
 /// Error code for some of the stepgen operations.
 #[derive(Debug, PartialEq)]
 pub enum Error {
-    /// Requested parameter (acceleration or speed) is too slow -- delay is too long and does not
-    /// fit in 16.8 format.
+    /// Requested parameter (acceleration, speed or shaper frequency) is too slow -- delay is
+    /// too long and does not fit in 16.8 format, or (for `ShapedStepgen`) the impulse spacing
+    /// it implies is too long to fit in the shaper's fixed-size window.
     TooSlow,
 
-    /// Requested speed is too fast -- delay is to short for the MCU to process it timely.
+    /// Requested speed is too fast -- delay is to short for the MCU to process it timely. Also
+    /// returned by `ShapedStepgen::new` if the shaper frequency/damping ratio don't describe an
+    /// underdamped resonance worth cancelling, or if `inner` has multi-stepping enabled.
     TooFast,
 
     /// Speed or acceleration was not configured when step is set.
@@ -58,6 +61,11 @@ pub type Result = core::result::Result<(), Error>;
 // Smallest delay we can handle without significant rounding errors
 const FASTEST_DELAY: u32 = 30;
 
+// Capacity of the ring buffers `ShapedStepgen` uses to hold raw step times that still have
+// un-applied delayed impulses. Sized generously for the slowest resonant frequencies we expect
+// anyone to shape against; `ShapedStepgen::new` rejects configurations that would overflow it.
+const MAX_SHAPER_WINDOW: usize = 64;
+
 /// State of the stepgen.
 #[derive(Debug)]
 pub struct Stepgen {
@@ -81,26 +89,92 @@ pub struct Stepgen {
     target_step: u32,
     // Target speed delay, in 16.16 format
     target_delay: u32,
+
+    // Maximum number of steps `next_batch` may fold into a single timer tick.
+    max_steps_per_tick: u32,
+
+    // Sub-tick units per timer tick `next_time` accumulates in.
+    time_resolution: u64,
+    // Absolute elapsed time so far, in `time_resolution` sub-tick units.
+    elapsed_time: u64,
+    // Sub-tick remainder (in units of `1 / (1 << 16)` of a sub-tick) carried forward between
+    // `next_time` calls so truncating the per-step delay to whole sub-ticks never compounds.
+    time_remainder: u64,
 }
 
-/// This function computes square root of an `u64` number.
-fn u64sqrt(x0: u64) -> u64 {
-    let mut x = x0;
-    let mut xr = 0; // result register
-    let mut q2 = 0x4000_0000_0000_0000u64; // scan-bit register, set to highest possible result bit
-    while q2 != 0 {
-        if (xr + q2) <= x {
-            x -= xr + q2;
-            xr >>= 1;
-            xr += q2; // test flag
-        } else {
-            xr >>= 1;
+/// This function computes square root of an `u64` number, rounded to the nearest integer.
+///
+/// Uses Newton-Raphson, seeded from a `leading_zeros`-based estimate so it only takes a
+/// handful of iterations to converge, which is considerably cheaper on a Cortex-M than scanning
+/// all 32 result bits one at a time. See `sqrt_matches_reference_*` for a property test checking
+/// this against `u64sqrt_reference`, the old bit-by-bit implementation kept around for that.
+const fn u64sqrt(n: u64) -> u64 {
+    if n == 0 {
+        return 0;
+    }
+
+    // x ~ sqrt(n): n has (64 - leading_zeros) significant bits, so sqrt(n) has about half as
+    // many; round the estimate up so we start above the true root and only converge downward.
+    let mut x = 1u64 << ((65 - n.leading_zeros() as u64) / 2);
+    loop {
+        let next = (x + n / x) / 2;
+        if next >= x {
+            break;
         }
-        q2 >>= 2; // shift twice
+        x = next;
+    }
+    // Newton-Raphson with floor division can leave `x` one above floor(sqrt(n)); correct it.
+    while x * x > n {
+        x -= 1;
     }
 
-    // add for rounding, if necessary
-    if xr < x { xr + 1 } else { xr }
+    // Round to nearest, matching the old bit-by-bit implementation's rounding rule.
+    let residual = n - x * x;
+    if residual > x { x + 1 } else { x }
+}
+
+/// Approximates `exp(-y)` for `y` given in 16.16 fixed-point format, returning the result in
+/// the same format. Uses the identity `exp(-y) = lim (1 - y/n)^n` for `n = 1 << EXP_STEPS`:
+/// `y/n` is small enough that the linear term is a good approximation of `exp(-y/n)`, and
+/// repeated squaring turns that into `exp(-y)` without needing a transcendental function --
+/// the same kind of bit-twiddling trick `u64sqrt` uses to avoid one.
+fn fixed_exp_neg(y: u32) -> u32 {
+    const EXP_STEPS: u32 = 12;
+    let base = (1u64 << 16).saturating_sub(u64::from(y) >> EXP_STEPS);
+    let mut result = base;
+    for _ in 0..EXP_STEPS {
+        result = (result * result) >> 16;
+    }
+    result as u32
+}
+
+/// Computes the first-step delay (16.16 format), the core of `set_acceleration`, as a `const
+/// fn` so it can run at compile time. Used by both `set_acceleration` and
+/// `Stepgen::with_acceleration`, the latter of which actually gets a pre-baked `Stepgen` out of
+/// it -- this function alone only moves where the computation happens, not where its result ends
+/// up.
+///
+/// # Errors
+/// Returns `Error::TooSlow` if the first delay does not fit into 16.8 format (our timer is
+/// only 16 bit).
+pub const fn first_delay_for(ticks_per_second: u32, acceleration: u32) -> core::result::Result<u32, Error> {
+    // c0 = F*sqrt(2/a)*.676 = F*sqrt(2/a)*676/1000 =
+    //      F*sqrt(2*676*676/a)/1000 =
+    //      F*sqrt(2*676*676*1^16)/(1000*1^8)
+    // We bring as much as we can under square root, to increase accuracy of division
+    // sqrt(1 << 16) is (1 << 8), which is to convert to 24.8
+    // We shift 24 bits to the left to adjust for acceleration in 24.8 format plus to convert
+    // result into 24.8 format, so the resulting shift is 40 bits.
+    // 676 is used to correct for the first step (see the linked paper)
+    let c0long: u64 = ((2u64 * 676 * 676) << 40) / acceleration as u64;
+    let c0: u64 = (ticks_per_second as u64 * u64sqrt(c0long) / 1000) >> 8;
+    if (c0 >> 24) != 0 {
+        // Doesn't fit in 16.8 format, our timer is only 16 bit.
+        Err(Error::TooSlow)
+    } else {
+        // Convert to 16.16 format. We only need this precision during intermediate calculations.
+        Ok((c0 as u32) << 8)
+    }
 }
 
 impl Stepgen {
@@ -117,6 +191,47 @@ impl Stepgen {
             first_delay: 0,
             target_step: 0,
             target_delay: 0,
+            max_steps_per_tick: 1,
+            time_resolution: 1,
+            elapsed_time: 0,
+            time_remainder: 0,
+        }
+    }
+
+    /// Like `new` followed by `set_acceleration`, but callable from a `const` context (e.g. to
+    /// bake a `static`/`const` `Stepgen` with its acceleration already set at compile time,
+    /// instead of paying `set_acceleration`'s `u64sqrt` call on the MCU at startup).
+    ///
+    /// # Errors
+    /// Returns `Error::TooSlow` if the first delay does not fit into 16.8 format (our timer is
+    /// only 16 bit), same as `set_acceleration`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use stepgen::Stepgen;
+    ///
+    /// const STEPGEN: Result<Stepgen, stepgen::Error> = Stepgen::with_acceleration(1_000_000, 1000 << 8);
+    /// let mut stepgen = STEPGEN.unwrap();
+    /// stepgen.set_target_speed(800 << 8).unwrap();
+    /// ```
+    pub const fn with_acceleration(ticks_per_second: u32, acceleration: u32) -> core::result::Result<Stepgen, Error> {
+        match first_delay_for(ticks_per_second, acceleration) {
+            Ok(first_delay) => Ok(Stepgen {
+                current_step: 0,
+                speed: 0,
+                delay: 0,
+                slewing_delay: 0,
+                ticks_per_second,
+                first_delay,
+                target_step: 0,
+                target_delay: 0,
+                max_steps_per_tick: 1,
+                time_resolution: 1,
+                elapsed_time: 0,
+                time_remainder: 0,
+            }),
+            Err(e) => Err(e),
         }
     }
 
@@ -151,22 +266,7 @@ impl Stepgen {
     /// assert_eq!(Error::TooSlow, stepper.set_acceleration(1 << 8).unwrap_err());
     /// ```
     pub fn set_acceleration(&mut self, acceleration: u32) -> Result {
-        // c0 = F*sqrt(2/a)*.676 = F*sqrt(2/a)*676/1000 =
-        //      F*sqrt(2*676*676/a)/1000 =
-        //      F*sqrt(2*676*676*1^16)/(1000*1^8)
-        // We bring as much as we can under square root, to increase accuracy of division
-        // sqrt(1 << 16) is (1 << 8), which is to convert to 24.8
-        // We shift 24 bits to the left to adjust for acceleration in 24.8 format plus to convert
-        // result into 24.8 format, so the resulting shift is 40 bits.
-        // 676 is used to correct for the first step (see the linked paper)
-        let c0long: u64 = ((2u64 * 676 * 676) << 40) / u64::from(acceleration);
-        let c0: u64 = (u64::from(self.ticks_per_second) * u64sqrt(c0long) / 1000) >> 8;
-        if (c0 >> 24) != 0 {
-            // Doesn't fit in 16.8 format, our timer is only 16 bit.
-            return Err(Error::TooSlow);
-        }
-        // Convert to 16.16 format. We only need this precision during intermediate calculations.
-        self.first_delay = (c0 as u32) << 8;
+        self.first_delay = first_delay_for(self.ticks_per_second, acceleration)?;
         Ok(())
     }
 
@@ -228,7 +328,12 @@ impl Stepgen {
             // Too slow, doesn't fit in in 16.8 format, our timer is only 16 bit.
             return Err(Error::TooSlow);
         }
-        if delay <= u64::from(FASTEST_DELAY) * (1 << 8) {
+        // With multi-stepping (`set_max_steps_per_tick`), `next_batch` can fold up to
+        // `max_steps_per_tick` steps into a single timer tick, so only the *batch* delay needs
+        // to clear `FASTEST_DELAY`, not every individual step's ideal delay. Call
+        // `set_max_steps_per_tick` before this to have it take effect here.
+        let min_delay = u64::from(FASTEST_DELAY) * (1 << 8) / u64::from(self.max_steps_per_tick);
+        if delay <= min_delay {
             // Too fast, less than 10 ticks of a timer. 10 is an arbitrary number,
             // just to make sure we have enough time to calculate next delay.
             return Err(Error::TooFast);
@@ -238,6 +343,60 @@ impl Stepgen {
         Ok(())
     }
 
+    /// Sets the maximum number of steps `next_batch` may fold into a single timer tick once the
+    /// ideal per-step delay drops below `FASTEST_DELAY`, and lets `set_target_speed` accept
+    /// speeds that fast instead of rejecting them with `Error::TooFast`. Defaults to `1` (no
+    /// multi-stepping; `next_batch` then always behaves
+    /// like `next`, one step per tick).
+    ///
+    /// This is the "switch to double/multi stepping" trick high-microstepping setups need to
+    /// exceed the step rate a single timer tick can otherwise sustain.
+    ///
+    /// # Notes
+    /// Call this before `set_target_speed`: `set_target_speed` only allows a target speed whose
+    /// per-step delay clears `FASTEST_DELAY / max_steps_per_tick`, so a larger
+    /// `max_steps_per_tick` set afterwards has no effect on a speed already accepted under the
+    /// stricter, single-stepping limit.
+    pub fn set_max_steps_per_tick(&mut self, max_steps_per_tick: u32) {
+        self.max_steps_per_tick = if max_steps_per_tick == 0 { 1 } else { max_steps_per_tick };
+    }
+
+    /// Sets the number of sub-tick units `next_time` accumulates per timer tick. Defaults to
+    /// `1` (absolute time is reported in whole timer ticks). Pick something finer when the
+    /// consumer needs to schedule against a shared clock with higher resolution than this
+    /// stepgen's own tick -- e.g. keeping several axes of a coordinated move in sync with more
+    /// precision than any single one's timer period offers.
+    ///
+    /// Resets the time `next_time` has accumulated so far back to `0`.
+    pub fn set_time_resolution(&mut self, time_resolution: u64) {
+        self.time_resolution = if time_resolution == 0 { 1 } else { time_resolution };
+        self.elapsed_time = 0;
+        self.time_remainder = 0;
+    }
+
+    /// Like `next()`, but returns the absolute time (in `time_resolution` sub-tick units, whole
+    /// ticks by default) at which the step should fire, rather than the delay since the
+    /// previous one. `None` once stopped, same as `next()`.
+    ///
+    /// `next_delay`/`next()` truncate the exact 16.16 per-step delay down to 16.8 before
+    /// returning it, and summing thousands of those truncated delays lets the rounding error
+    /// slowly accumulate -- the slewing delay "could be different due to the accumulated
+    /// rounding errors" noted in `next_delay_exact`. `next_time` instead accumulates the exact
+    /// delay and carries the leftover sub-tick fraction forward in `time_remainder`, rounding
+    /// only the reported time to the nearest sub-tick, so the running error stays within half a
+    /// sub-tick instead of compounding across a long move.
+    pub fn next_time(&mut self) -> Option<u64> {
+        let delay = self.next_delay_exact();
+        if delay == 0 {
+            return None;
+        }
+        let scaled = u64::from(delay) * self.time_resolution + self.time_remainder;
+        self.time_remainder = scaled & 0xffff;
+        self.elapsed_time += scaled >> 16;
+        // Report the time rounded to the nearest sub-tick; the fraction stays in `time_remainder`.
+        Some(self.elapsed_time + (self.time_remainder >> 15))
+    }
+
     /// Current step stepgen is at.
     pub fn current_step(&self) -> u32 {
         self.current_step
@@ -262,8 +421,16 @@ impl Stepgen {
         }
     }
 
-    /// Returns '0' if should stop. Otherwise, returns timer delay in 24.8 format
+    /// Returns '0' if should stop. Otherwise, returns timer delay in 16.8 format.
     fn next_delay(&mut self) -> u32 {
+        self.next_delay_exact() >> 8
+    }
+
+    /// Same as `next_delay`, but returns the delay in its native 16.16 format instead of
+    /// truncating to 16.8. `next_delay` throws away the low 8 bits on every single call, which
+    /// is where the rounding error `next_time` avoids by accumulating this value directly comes
+    /// from.
+    fn next_delay_exact(&mut self) -> u32 {
         let target_step = self.target_step;
         let target_delay = self.target_delay;
         let st = self.current_step;
@@ -292,7 +459,7 @@ impl Stepgen {
                 self.speed = 1;
                 self.delay
             };
-            return d >> 8; // Convert to 16.8 format
+            return d;
         }
 
         // Calculate the projected step we would stop at if we start decelerating right now
@@ -327,10 +494,53 @@ impl Stepgen {
 
         // If slewing, return slew delay. delay should be close enough, but could
         // be different due to the accumulated rounding errors
-        let d = if self.slewing_delay != 0 { self.slewing_delay } else { self.delay };
-        d >> 8 // Convert to 16.8 format
+        if self.slewing_delay != 0 { self.slewing_delay } else { self.delay }
     }
 
+    /// Like `next()`, but if the ideal per-step delay is shorter than `FASTEST_DELAY`, combines
+    /// up to `max_steps_per_tick` (see `set_max_steps_per_tick`) steps into a single timer tick
+    /// instead of returning a delay the MCU can't keep up with. Returns `(delay, steps)`: wait
+    /// `delay` ticks (16.8 format) then pulse the step pin `steps` times.
+    ///
+    /// `None` is returned once stopped, same as `next()`.
+    ///
+    /// # Notes
+    /// Folded-in steps are not individually re-evaluated against acceleration/slewing -- they
+    /// share the delay computed for the first step of the batch -- so a batch spanning the
+    /// target step may overshoot it by up to `max_steps_per_tick - 1` steps. Callers that need
+    /// to stop exactly at `target_step` should fall back to `next()` as they approach it.
+    ///
+    /// If `set_max_steps_per_tick` is lowered after `set_target_speed` already validated the
+    /// current target speed against a larger budget, `max_steps_per_tick` alone can no longer
+    /// fold in enough steps to clear `FASTEST_DELAY`. Rather than honor the now-too-small cap
+    /// and silently return a batch delay under that floor, this folds in as many steps as
+    /// clearing the floor requires, exceeding `max_steps_per_tick` for that one call -- timer
+    /// safety wins over the configured cap.
+    pub fn next_batch(&mut self) -> Option<(u32, u32)> {
+        let delay = self.next_delay();
+        if delay == 0 {
+            return None;
+        }
+
+        let min_delay = FASTEST_DELAY << 8;
+        if delay >= min_delay || self.max_steps_per_tick <= 1 {
+            return Some((delay, 1));
+        }
+
+        let needed = min_delay.div_ceil(delay);
+        let mut steps = needed;
+        if steps > self.max_steps_per_tick {
+            steps = self.max_steps_per_tick;
+        }
+        if delay * steps < min_delay {
+            // The cap is too small for the current per-step delay to stay timer-safe -- fall
+            // back to the uncapped `needed`, which clears the floor by construction.
+            steps = needed;
+        }
+        // next_delay() already advanced current_step by one for the first step in the batch.
+        self.current_step += steps - 1;
+        Some((delay * steps, steps))
+    }
 
     fn speedup(&mut self) {
         let denom = 4 * self.speed + 1;
@@ -356,6 +566,288 @@ impl Iterator for Stepgen {
     }
 }
 
+/// Input shaping kernel convolved with the step stream by `ShapedStepgen` to cancel residual
+/// mechanical resonance (belt/frame ringing) at a configured frequency.
+///
+/// Both kernels are causal (no negative impulses), so they only ever add latency, never change
+/// the total step count: `Zv` delays the move by `Δ`, `Zvd` by `2·Δ`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShaperKind {
+    /// Zero Vibration: two impulses, amplitudes `A1 : A2 = 1 : K` at offsets `0, Δ`.
+    Zv,
+    /// Zero Vibration Derivative: three impulses, amplitudes `A1 : A2 : A3 = 1 : 2K : K²` at
+    /// offsets `0, Δ, 2Δ`. Costs twice the latency of `Zv`, but tolerates the resonant
+    /// frequency being somewhat mistuned.
+    Zvd,
+}
+
+/// Wraps a `Stepgen` with a ZV/ZVD input shaper that spreads each step across 2 or 3
+/// time-shifted, weighted impulses to cancel ringing at a resonant frequency, the same
+/// technique "axis shaping" firmware (e.g. Duet) uses to raise the top speed achievable on
+/// belt-driven axes without retuning acceleration.
+///
+/// Internally, the unshaped step stream is a staircase function `x(t)` (the step count reached
+/// by time `t`); the shaper forms `x_s(t) = Σ A_k·x(t − Δ_k)` and emits a step whenever `x_s(t)`
+/// crosses the next integer. Since each `x(t - Δ_k)` only ever jumps by 1 at the `k`-th delayed
+/// copy of a raw step's arrival time, this reduces to replaying the raw step times through a
+/// small set of fixed offsets and accumulating their amplitudes in arrival order; two ring
+/// buffers (`queue1`, `queue2`) hold the raw arrival times still waiting for their `Δ`- and
+/// `2Δ`-delayed contribution, sized under the assumption that raw steps never arrive closer
+/// together than `FASTEST_DELAY` -- `ShapedStepgen::new` rejects an `inner` configured for
+/// multi-stepping (`Stepgen::set_max_steps_per_tick`) up front so that assumption always holds.
+#[derive(Debug)]
+pub struct ShapedStepgen {
+    inner: Stepgen,
+    kind: ShaperKind,
+    // Impulse amplitudes, 16.16 format, summing to `1 << 16`. Index 2 is unused for `Zv`.
+    amplitude: [u32; 3],
+    // Spacing between impulses, in whole timer ticks.
+    delta: u32,
+
+    // Raw step arrival times (absolute ticks) waiting for their `Δ`-delayed impulse.
+    queue1: [u32; MAX_SHAPER_WINDOW],
+    queue1_head: usize,
+    queue1_len: usize,
+    // Raw step arrival times waiting for their `2Δ`-delayed impulse (`Zvd` only).
+    queue2: [u32; MAX_SHAPER_WINDOW],
+    queue2_head: usize,
+    queue2_len: usize,
+
+    // Absolute time (whole ticks) of the most recently pulled raw step, valid iff `have_raw`.
+    raw_time: u32,
+    have_raw: bool,
+    // Accumulated shaped position, 16.16 fixed-point step count.
+    shaped_pos: u64,
+    // Number of shaped steps emitted so far.
+    steps_emitted: u32,
+    // Absolute time (whole ticks) of the last emitted shaped step.
+    last_emit: u32,
+    finished: bool,
+}
+
+impl ShapedStepgen {
+    /// Wraps `inner` with an input shaper of the given `kind`, tuned to cancel a resonance at
+    /// `frequency` Hz (16.8 format, matching the rest of this crate) with damping ratio `zeta`
+    /// (0.16 format: the real ratio is `zeta as f64 / 65536.0`, always in `[0, 1)`).
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::TooFast` if `frequency` is zero, `zeta` describes an overdamped or
+    /// critically damped system (`zeta >= 1`), or `inner` has multi-stepping enabled (see
+    /// `Stepgen::set_max_steps_per_tick`) -- the ring buffers below are sized against raw steps
+    /// never arriving closer together than `FASTEST_DELAY`, an assumption multi-stepping breaks
+    /// by design. Returns `Error::TooSlow` if the resulting impulse spacing `Δ` is too large for
+    /// the shaper's fixed-size window -- i.e. the resonant frequency is too low relative to
+    /// `inner`'s step rate.
+    pub fn new(inner: Stepgen, kind: ShaperKind, frequency: u32, zeta: u32) -> core::result::Result<ShapedStepgen, Error> {
+        if inner.max_steps_per_tick != 1 {
+            return Err(Error::TooFast);
+        }
+        if frequency == 0 {
+            return Err(Error::TooFast);
+        }
+
+        // damped = sqrt(1 - zeta^2), 0.16 format. zeta is 0.16, so zeta^2 is 0.32.
+        let zeta2 = u64::from(zeta) * u64::from(zeta);
+        let one_0_32 = 1u64 << 32;
+        if zeta2 >= one_0_32 {
+            return Err(Error::TooFast);
+        }
+        let damped = u64sqrt(one_0_32 - zeta2);
+        if damped == 0 {
+            return Err(Error::TooFast);
+        }
+
+        // K = exp(-zeta*pi/damped), computed in 16.16 throughout.
+        const PI_16_16: u64 = 205_887; // pi * (1 << 16), rounded
+        let arg = (u64::from(zeta) * PI_16_16 / damped) as u32;
+        let k = u64::from(fixed_exp_neg(arg));
+
+        let one = 1u64 << 16;
+        let onek = one + k;
+        let (numerator, denominator) = match kind {
+            ShaperKind::Zv => ([one, k, 0], onek),
+            ShaperKind::Zvd => ([one, 2 * k, (k * k) >> 16], (onek * onek) >> 16),
+        };
+        // Round each amplitude, but derive the *last* one as the remainder rather than rounding
+        // it too -- plain per-term rounding can leave the amplitudes summing to slightly less
+        // than `1 << 16` (for `Zvd`, e.g. `K = 1050 / 65536` sums to 65535), and `shaped_pos` accumulates that
+        // deficit once per raw step, so over a long move it never reaches the final threshold
+        // and `ShapedStepgen` stops one or more steps short. Deriving the last term as `(1 <<
+        // 16) - (the others)` makes the sum exact by construction.
+        let mut amplitude = [0u32; 3];
+        amplitude[0] = (((numerator[0] << 16) + denominator / 2) / denominator) as u32;
+        match kind {
+            ShaperKind::Zv => {
+                amplitude[1] = (1u32 << 16) - amplitude[0];
+            }
+            ShaperKind::Zvd => {
+                amplitude[1] = (((numerator[1] << 16) + denominator / 2) / denominator) as u32;
+                amplitude[2] = (1u32 << 16) - amplitude[0] - amplitude[1];
+            }
+        }
+
+        // delta (ticks) = ticks_per_second / (2 * frequency_hz * damped), with frequency in
+        // 16.8 and damped in 0.16: delta = ticks_per_second*256*65536 / (2*frequency*damped).
+        let ticks_per_second = u64::from(inner.ticks_per_second);
+        let denom = 2 * u64::from(frequency) * damped;
+        let delta = (ticks_per_second * 256 * 65536 + denom / 2) / denom;
+        if delta == 0 {
+            return Err(Error::TooFast);
+        }
+        let delta = delta as u32;
+
+        // `queue2` (`Zvd` only) holds raw times spanning `2Δ`, so it needs twice the window.
+        let span = if kind == ShaperKind::Zvd { 2 * u64::from(delta) } else { u64::from(delta) };
+        let window_needed = span / u64::from(FASTEST_DELAY) + 1;
+        if window_needed > MAX_SHAPER_WINDOW as u64 {
+            return Err(Error::TooSlow);
+        }
+
+        Ok(ShapedStepgen {
+            inner,
+            kind,
+            amplitude,
+            delta,
+            queue1: [0; MAX_SHAPER_WINDOW],
+            queue1_head: 0,
+            queue1_len: 0,
+            queue2: [0; MAX_SHAPER_WINDOW],
+            queue2_head: 0,
+            queue2_len: 0,
+            raw_time: 0,
+            have_raw: false,
+            shaped_pos: 0,
+            steps_emitted: 0,
+            last_emit: 0,
+            finished: false,
+        })
+    }
+
+    /// Current step the wrapped `Stepgen` is at (shaping only delays when steps are emitted,
+    /// it never changes how many there are).
+    pub fn current_step(&self) -> u32 {
+        self.inner.current_step()
+    }
+
+    /// Unwraps the shaper, giving back the underlying `Stepgen`.
+    pub fn into_inner(self) -> Stepgen {
+        self.inner
+    }
+
+    fn queue_push(queue: &mut [u32; MAX_SHAPER_WINDOW], head: &mut usize, len: &mut usize, time: u32) {
+        let idx = (*head + *len) % MAX_SHAPER_WINDOW;
+        queue[idx] = time;
+        *len += 1;
+    }
+
+    fn queue_front(queue: &[u32; MAX_SHAPER_WINDOW], head: usize, len: usize) -> Option<u32> {
+        if len == 0 { None } else { Some(queue[head]) }
+    }
+
+    fn queue_pop(head: &mut usize, len: &mut usize) {
+        *head = (*head + 1) % MAX_SHAPER_WINDOW;
+        *len -= 1;
+    }
+
+    fn next_shaped(&mut self) -> Option<u32> {
+        if self.finished {
+            return None;
+        }
+
+        loop {
+            if !self.have_raw {
+                match self.inner.next_delay() {
+                    0 => (),
+                    d => {
+                        // Round to a whole tick, once per raw arrival. Each raw delay is rounded
+                        // on its own and its fraction is not carried forward, so the rounding
+                        // errors of successive raw steps add up over a long move.
+                        self.raw_time += (d + 128) >> 8;
+                        self.have_raw = true;
+                    }
+                }
+            }
+
+            let raw_candidate = if self.have_raw { Some(self.raw_time) } else { None };
+            let q1_candidate = Self::queue_front(&self.queue1, self.queue1_head, self.queue1_len)
+                .map(|t| t + self.delta);
+            let q2_candidate = if self.kind == ShaperKind::Zvd {
+                Self::queue_front(&self.queue2, self.queue2_head, self.queue2_len)
+                    .map(|t| t + 2 * self.delta)
+            } else {
+                None
+            };
+
+            // Pick whichever pending impulse occurs first; ties favor the raw arrival so a
+            // freshly-arriving step's own (undelayed) impulse is applied before older ones'
+            // delayed contributions land on the same tick.
+            let mut chosen: Option<(u32, u8)> = raw_candidate.map(|t| (t, 0));
+            if let Some(t) = q1_candidate {
+                let better = match chosen {
+                    Some((bt, _)) => t < bt,
+                    None => true,
+                };
+                if better {
+                    chosen = Some((t, 1));
+                }
+            }
+            if let Some(t) = q2_candidate {
+                let better = match chosen {
+                    Some((bt, _)) => t < bt,
+                    None => true,
+                };
+                if better {
+                    chosen = Some((t, 2));
+                }
+            }
+
+            let (time, which) = match chosen {
+                None => {
+                    self.finished = true;
+                    return None;
+                }
+                Some(v) => v,
+            };
+
+            match which {
+                0 => {
+                    self.shaped_pos += u64::from(self.amplitude[0]);
+                    Self::queue_push(&mut self.queue1, &mut self.queue1_head, &mut self.queue1_len, time);
+                    if self.kind == ShaperKind::Zvd {
+                        Self::queue_push(&mut self.queue2, &mut self.queue2_head, &mut self.queue2_len, time);
+                    }
+                    self.have_raw = false;
+                }
+                1 => {
+                    self.shaped_pos += u64::from(self.amplitude[1]);
+                    Self::queue_pop(&mut self.queue1_head, &mut self.queue1_len);
+                }
+                _ => {
+                    self.shaped_pos += u64::from(self.amplitude[2]);
+                    Self::queue_pop(&mut self.queue2_head, &mut self.queue2_len);
+                }
+            }
+
+            let threshold = (u64::from(self.steps_emitted) + 1) << 16;
+            if self.shaped_pos >= threshold {
+                self.steps_emitted += 1;
+                let delay = time - self.last_emit;
+                self.last_emit = time;
+                return Some(delay << 8); // back to 16.8, matching Stepgen's Iterator output
+            }
+        }
+    }
+}
+
+impl Iterator for ShapedStepgen {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_shaped()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -377,12 +869,78 @@ mod tests {
         assert_eq!(0x1_00_00_00_00u64, u64sqrt(0xffff_ffff_ffff_ffffu64));
     }
 
+    // Original bit-by-bit `u64sqrt`, kept only as a slow-but-trusted oracle for
+    // `sqrt_matches_reference_*` below, since it predates the Newton-Raphson version.
+    fn u64sqrt_reference(x0: u64) -> u64 {
+        let mut x = x0;
+        let mut xr = 0; // result register
+        let mut q2 = 0x4000_0000_0000_0000u64; // scan-bit register, set to highest possible result bit
+        while q2 != 0 {
+            if (xr + q2) <= x {
+                x -= xr + q2;
+                xr >>= 1;
+                xr += q2; // test flag
+            } else {
+                xr >>= 1;
+            }
+            q2 >>= 2; // shift twice
+        }
+
+        // add for rounding, if necessary
+        if xr < x { xr + 1 } else { xr }
+    }
+
+    #[test]
+    fn sqrt_matches_reference_u32_range() {
+        // Exhaustively checking all 2^32 values is too slow to run on every `cargo test`, so we
+        // check every 97th value from 0 up (the same values on every run) and rely on
+        // `sqrt_matches_reference_random_u64` below for additional, wider-range coverage.
+        let mut n: u64 = 0;
+        while n <= u64::from(core::u32::MAX) {
+            assert_eq!(u64sqrt_reference(n), u64sqrt(n), "mismatch for n = {}", n);
+            n += 97;
+        }
+    }
+
+    #[test]
+    fn sqrt_matches_reference_random_u64() {
+        // Simple LCG so this has no dependency on an external `rand` crate and is reproducible.
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        for _ in 0..200_000 {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            assert_eq!(u64sqrt_reference(seed), u64sqrt(seed), "mismatch for n = {}", seed);
+        }
+    }
+
     #[test]
     fn acceleration_too_slow() {
         let mut stepgen = Stepgen::new(FREQUENCY);
         assert_eq!(Err(Error::TooSlow), stepgen.set_acceleration(1 << 8));
     }
 
+    #[test]
+    fn with_acceleration_matches_set_acceleration() {
+        let mut via_setter = Stepgen::new(FREQUENCY);
+        via_setter.set_acceleration(1000 << 8).unwrap();
+
+        let via_const = Stepgen::with_acceleration(FREQUENCY, 1000 << 8).unwrap();
+        assert_eq!(via_setter.first_delay, via_const.first_delay);
+    }
+
+    #[test]
+    fn with_acceleration_too_slow() {
+        assert_eq!(Error::TooSlow, Stepgen::with_acceleration(FREQUENCY, 1 << 8).unwrap_err());
+    }
+
+    const BAKED_STEPGEN: core::result::Result<Stepgen, Error> = Stepgen::with_acceleration(FREQUENCY, 1000 << 8);
+
+    #[test]
+    fn with_acceleration_is_const_evaluable() {
+        // The whole point of `with_acceleration` is that this works in a `const` context --
+        // `BAKED_STEPGEN` above is computed at compile time, not when this test runs.
+        assert_eq!(Ok(BAKED_STEPGEN.unwrap().first_delay), Stepgen::with_acceleration(FREQUENCY, 1000 << 8).map(|s| s.first_delay));
+    }
+
     #[test]
     fn too_slow() {
         let mut stepgen = Stepgen::new(FREQUENCY);
@@ -486,4 +1044,172 @@ mod tests {
         stepgen.set_target_speed(800 << 8).unwrap();
         assert_eq!(Err(Error::SpeedAccelerationNotSet), stepgen.set_target_step(1000_000_000));
     }
+
+    fn shaped_stepgen(kind: ShaperKind, frequency: u32, zeta: u32, target_step: u32) -> ShapedStepgen {
+        let mut stepgen = Stepgen::new(FREQUENCY);
+        stepgen.set_acceleration(1000 << 8).unwrap();
+        stepgen.set_target_speed(800 << 8).unwrap();
+        stepgen.set_target_step(target_step).unwrap();
+        ShapedStepgen::new(stepgen, kind, frequency, zeta).unwrap()
+    }
+
+    #[test]
+    fn shaper_amplitudes_sum_to_exactly_one() {
+        // Regression test for amplitudes that, rounded independently, summed to 65535 instead
+        // of 65536 (possible for `Zvd`, e.g. `K = 1050 / 65536`) -- checked across a spread of damping ratios for both
+        // shaper kinds.
+        for &zeta in &[0u32, 1000, 6554, 13107, 32768, 45000, 60000] {
+            let zv = shaped_stepgen(ShaperKind::Zv, 2000 << 8, zeta, 1);
+            assert_eq!(1u32 << 16, zv.amplitude[0] + zv.amplitude[1]);
+
+            let zvd = shaped_stepgen(ShaperKind::Zvd, 2000 << 8, zeta, 1);
+            assert_eq!(1u32 << 16, zvd.amplitude[0] + zvd.amplitude[1] + zvd.amplitude[2]);
+        }
+    }
+
+    #[test]
+    fn shaped_stepgen_reaches_exact_target_step() {
+        // Shaping must not change the step count: 5000 raw steps come out as 5000 shaped steps.
+        // Amplitudes summing to slightly under `1 << 16` would leave `shaped_pos` short of the
+        // final threshold and lose the last step.
+        let shaped = shaped_stepgen(ShaperKind::Zv, 2000 << 8, 13107, 5000);
+        let count = shaped.count();
+        assert_eq!(5000, count);
+    }
+
+    #[test]
+    fn shaped_stepgen_zvd_reaches_exact_target_step() {
+        let shaped = shaped_stepgen(ShaperKind::Zvd, 2000 << 8, 13107, 5000);
+        let count = shaped.count();
+        assert_eq!(5000, count);
+    }
+
+    #[test]
+    fn shaped_stepgen_rejects_zero_frequency() {
+        let stepgen = Stepgen::new(FREQUENCY);
+        assert_eq!(Error::TooFast, ShapedStepgen::new(stepgen, ShaperKind::Zv, 0, 13107).unwrap_err());
+    }
+
+    #[test]
+    fn shaped_stepgen_rejects_multi_stepping_inner() {
+        // Regression test: wrapping a `Stepgen` with multi-stepping enabled used to silently
+        // overrun `queue1`/`queue2`, since they're sized assuming raw steps never arrive closer
+        // together than `FASTEST_DELAY` -- an assumption multi-stepping breaks.
+        let mut stepgen = Stepgen::new(FREQUENCY);
+        stepgen.set_max_steps_per_tick(1000);
+        assert_eq!(Error::TooFast, ShapedStepgen::new(stepgen, ShaperKind::Zv, 2000 << 8, 13107).unwrap_err());
+    }
+
+    #[test]
+    fn shaped_stepgen_rejects_overdamped_zeta() {
+        let stepgen = Stepgen::new(FREQUENCY);
+        assert_eq!(Error::TooFast, ShapedStepgen::new(stepgen, ShaperKind::Zv, 2000 << 8, 1 << 16).unwrap_err());
+    }
+
+    #[test]
+    fn shaped_stepgen_rejects_window_overflow() {
+        // A very low resonant frequency implies a delta spacing that needs more ring buffer
+        // slots than `MAX_SHAPER_WINDOW` provides.
+        let stepgen = Stepgen::new(FREQUENCY);
+        assert_eq!(Error::TooSlow, ShapedStepgen::new(stepgen, ShaperKind::Zv, 1 << 8, 0).unwrap_err());
+    }
+
+    #[test]
+    fn shaped_stepgen_into_inner_preserves_current_step() {
+        let shaped = shaped_stepgen(ShaperKind::Zv, 2000 << 8, 13107, 10);
+        let inner = shaped.into_inner();
+        assert_eq!(0, inner.current_step());
+    }
+
+    #[test]
+    fn set_target_speed_rejects_too_fast_without_multi_stepping() {
+        // `max_steps_per_tick` defaults to 1, so this must keep behaving exactly like
+        // `set_target_speed` always has.
+        let mut stepgen = Stepgen::new(FREQUENCY);
+        assert_eq!(Err(Error::TooFast), stepgen.set_target_speed(1_000_000 << 8));
+    }
+
+    #[test]
+    fn next_batch_folds_steps_once_below_fastest_delay() {
+        let mut stepgen = Stepgen::new(FREQUENCY);
+        stepgen.set_max_steps_per_tick(4);
+        stepgen.set_acceleration(1_000_000 << 8).unwrap();
+        // 50_000 steps/s implies a 20-tick per-step delay, below `FASTEST_DELAY` (30 ticks), so
+        // this would be rejected by `set_target_speed` without the relaxed multi-stepping gate.
+        stepgen.set_target_speed(50_000 << 8).unwrap();
+        stepgen.set_target_step(core::u32::MAX).unwrap();
+
+        // Accelerate until the ideal per-step delay drops low enough that next_batch starts
+        // folding steps together.
+        let mut batch = stepgen.next_batch().unwrap();
+        let mut iterations = 0;
+        while batch.1 == 1 {
+            batch = stepgen.next_batch().unwrap();
+            iterations += 1;
+            assert!(iterations < 10_000, "never reached multi-stepping cruise speed");
+        }
+        let (delay, steps) = batch;
+        assert_eq!(2, steps);
+        // The batch delay must still clear the single-tick minimum even though the per-step
+        // delay alone does not -- that's the whole point of folding steps together.
+        assert!(delay >= FASTEST_DELAY << 8);
+        assert!(delay / steps < FASTEST_DELAY << 8);
+    }
+
+    #[test]
+    fn next_batch_stays_timer_safe_if_max_steps_per_tick_is_lowered_later() {
+        // Regression test: `set_max_steps_per_tick(10)` validates `set_target_speed` against a
+        // 10x budget, but lowering it to `2` afterwards used to leave `next_batch` capping the
+        // batch to 2 steps even though 2 steps aren't enough to clear `FASTEST_DELAY` at this
+        // speed -- returning a batch delay under the floor the feature exists to guarantee.
+        let mut stepgen = Stepgen::new(FREQUENCY);
+        stepgen.set_max_steps_per_tick(10);
+        stepgen.set_acceleration(1_000_000 << 8).unwrap();
+        stepgen.set_target_speed(100_000 << 8).unwrap(); // 10-tick ideal per-step delay
+        stepgen.set_target_step(core::u32::MAX).unwrap();
+        stepgen.set_max_steps_per_tick(2);
+
+        // Drive well past acceleration into the cruise (slewing) phase, where the per-step
+        // delay settles at the target speed's ~10-tick ideal delay.
+        let mut batch = (0, 0);
+        for _ in 0..50_000 {
+            batch = stepgen.next_batch().unwrap();
+        }
+        let (delay, steps) = batch;
+        assert!(delay >= FASTEST_DELAY << 8, "batch delay {} under the timer-safe floor", delay);
+        assert!(steps > 2, "expected the floor to win over the lowered max_steps_per_tick, got {} steps", steps);
+    }
+
+    #[test]
+    fn next_time_does_not_drift_over_long_move() {
+        let mut by_time = Stepgen::new(FREQUENCY);
+        by_time.set_acceleration(1000 << 8).unwrap();
+        by_time.set_target_speed(800 << 8).unwrap();
+        by_time.set_target_step(5000).unwrap();
+
+        let mut exact_sum = Stepgen::new(FREQUENCY);
+        exact_sum.set_acceleration(1000 << 8).unwrap();
+        exact_sum.set_target_speed(800 << 8).unwrap();
+        exact_sum.set_target_step(5000).unwrap();
+
+        // Running total of the exact (16.16) per-step delays, i.e. the infinite-precision
+        // elapsed time `next_time` is meant to track without compounding rounding error.
+        let mut cumulative_exact: u64 = 0;
+        loop {
+            let exact = exact_sum.next_delay_exact();
+            let time = by_time.next_time();
+            if exact == 0 {
+                assert!(time.is_none());
+                break;
+            }
+            cumulative_exact += u64::from(exact);
+            let expected = (cumulative_exact + (1 << 15)) >> 16;
+            let got = time.unwrap();
+            // The running error between `next_time`'s output and the true elapsed time must
+            // stay within half a tick no matter how many steps accumulate -- unlike summing
+            // `next_delay`'s already-truncated 16.8 output, whose error grows with step count.
+            assert!((got as i64 - expected as i64).abs() <= 1,
+                "next_time drifted: got {}, expected ~{}", got, expected);
+        }
+    }
 }